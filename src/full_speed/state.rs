@@ -1,123 +1,635 @@
 //! Static state that's 'owned' by a full-speed driver
 //!
-//! This module allocates the static memory or the USB drivers,
-//! and provides guidance on how to safely access this memory.
+//! Descriptor memory (the queue heads and transfer descriptors) is no
+//! longer allocated by this module. Instead, a caller instantiates
+//! [`State<EP>`] in a `static`, where `EP` is the number of endpoint queue
+//! heads (one per endpoint, per direction) the USB instance needs, and
+//! hands the driver a `&'static mut` reference to it. This lets the
+//! caller choose how many endpoints to pay for, and where the descriptor
+//! memory lives (DTCM, OCRAM, ...) via `#[link_section]`. `State::new`
+//! isn't `const` (`qh::Qh`/`td::Td` aren't `Copy`, so the `EP`-sized
+//! arrays have to be built element-by-element at runtime), so the static
+//! is initialized once at startup instead of in its declaration:
+//!
+//! ```ignore
+//! #[link_section = ".dtcm"]
+//! static mut EP_STATE: Option<State<4>> = None;
+//!
+//! // During startup, before the USB instance is used:
+//! let ep_state = unsafe {
+//!     EP_STATE = Some(State::new());
+//!     EP_STATE.as_mut().unwrap()
+//! };
+//! ```
 
-use crate::QH_COUNT;
 use crate::{qh, ral, td};
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of transfer descriptors queued per endpoint queue head
+///
+/// Chaining several dTDs lets the driver queue a new transfer before the
+/// controller has finished draining the current one, instead of forcing a
+/// full prime/complete round trip between every packet.
+const TDS_PER_QH: usize = 4;
 
 /// A list of transfer descriptors
 ///
-/// Supports 1 TD per QH (per endpoint direction)
+/// Supports `TDS_PER_QH` dTDs per QH (per endpoint direction), linked
+/// together so that several transfers can be in flight at once.
 #[repr(align(32))]
-struct TdList([td::Td; QH_COUNT]);
-const TD_LIST_INIT: TdList = TdList([
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-    td::Td::new(),
-]);
+struct TdList<const EP: usize>([[td::Td; TDS_PER_QH]; EP]);
 
 /// A list of queue heads
 ///
 /// One queue head per endpoint, per direction (default).
 #[repr(align(4096))]
-struct QhList([qh::Qh; QH_COUNT]);
-const QH_LIST_INIT: QhList = QhList([
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-    qh::Qh::new(),
-]);
-
-struct State {
-    qhs: QhList,
-    tds: TdList,
+struct QhList<const EP: usize>([qh::Qh; EP]);
+
+/// Number of slots in the bus event ring buffer
+///
+/// One slot always stays empty to tell a full queue apart from an empty
+/// one, so the queue holds at most `EVENT_QUEUE_CAPACITY - 1` events.
+///
+/// Bus events (reset/suspend/resume/port-change/error) are rare compared
+/// to how often the main loop polls, so this is sized generously. If the
+/// queue does fill up, `push` drops the newest event rather than
+/// reclaiming the oldest, since reclaiming would require the producer to
+/// also write `head`, which belongs solely to the consumer.
+const EVENT_QUEUE_CAPACITY: usize = 16;
+
+/// A bus event observed by the USB interrupt handler
+///
+/// Delivered to application code through [`State::poll_event`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusEvent {
+    /// The host issued a bus reset
+    Reset,
+    /// The host suspended the bus
+    Suspend,
+    /// The bus resumed from a suspend
+    Resume,
+    /// The port status changed (speed negotiated, connect state, ...)
+    PortChange,
+    /// The controller reported an error condition
+    Error,
+}
+
+/// A lock-free, single-producer/single-consumer ring buffer of bus events
+///
+/// The USB interrupt handler is the only producer (through
+/// [`EventQueue::push`]), and application code draining
+/// [`State::poll_event`] is the only consumer. `head` is written only by
+/// the consumer and `tail` only by the producer, so no lock is needed;
+/// the `Acquire`/`Release` pairing makes sure each side observes the
+/// other's writes to the backing array before it acts on the updated
+/// index.
+struct EventQueue {
+    events: UnsafeCell<[MaybeUninit<BusEvent>; EVENT_QUEUE_CAPACITY]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
 }
 
-const STATE_INIT: State = State {
-    qhs: QH_LIST_INIT,
-    tds: TD_LIST_INIT,
-};
+unsafe impl Sync for EventQueue {}
+
+impl EventQueue {
+    const fn new() -> Self {
+        EventQueue {
+            events: UnsafeCell::new([MaybeUninit::uninit(); EVENT_QUEUE_CAPACITY]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
 
-static mut USB1_STATE: State = STATE_INIT;
-static mut USB2_STATE: State = STATE_INIT;
+    /// Push `event` onto the queue
+    ///
+    /// If the queue is full, `event` is dropped: the producer never
+    /// touches `head`, which belongs solely to the consumer, so it can't
+    /// reclaim a slot to make room.
+    fn push(&self, event: BusEvent) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next_tail = (tail + 1) % EVENT_QUEUE_CAPACITY;
+        if next_tail == self.head.load(Ordering::Acquire) {
+            return;
+        }
+        unsafe {
+            (*self.events.get())[tail] = MaybeUninit::new(event);
+        }
+        self.tail.store(next_tail, Ordering::Release);
+    }
 
-unsafe fn state(usb: &ral::usb::Instance) -> &'static mut State {
-    match &**usb as *const _ {
-        ral::usb::USB1 => &mut USB1_STATE,
-        ral::usb::USB2 => &mut USB2_STATE,
-        _ => unreachable!("ral module ensures that the USB instance is one of these two value"),
+    /// Pop the oldest unread event, if any
+    fn pop(&self) -> Option<BusEvent> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let event = unsafe { (*self.events.get())[head].assume_init() };
+        self.head
+            .store((head + 1) % EVENT_QUEUE_CAPACITY, Ordering::Release);
+        Some(event)
     }
 }
 
-/// Returns a pointer to the queue heads collection for this USB instance
+/// Descriptor memory for `EP` endpoint queue heads, owned by the caller
 ///
-/// This is only safe to use when assigning the ENDPTLISTADDR to the USB
-/// instance.
-pub fn assign_endptlistaddr(usb: &ral::usb::Instance) {
-    let ptr = unsafe { state(usb).qhs.0.as_ptr() };
-    ral::write_reg!(ral::usb, usb, ASYNCLISTADDR, ptr as u32);
+/// Place this in a `static`, then pass a `&'static mut` reference to the
+/// driver for the USB instance this memory belongs to.
+pub struct State<const EP: usize> {
+    qhs: QhList<EP>,
+    tds: TdList<EP>,
+    events: EventQueue,
 }
 
-/// "Steal" the queue heads for this USB state, and return an array of references to queue
-/// heads
+impl<const EP: usize> State<EP> {
+    /// Create descriptor memory for `EP` endpoint queue heads
+    ///
+    /// Neither `qh::Qh` nor `td::Td` implement `Copy`, so the `EP`-sized
+    /// arrays can't use the `[x; EP]` repeat operator; build them
+    /// element-by-element instead, the same way `steal_qhs`/`steal_tds`
+    /// already build their output arrays.
+    pub fn new() -> Self {
+        State {
+            qhs: QhList(core::array::from_fn(|_| qh::Qh::new())),
+            tds: TdList(core::array::from_fn(|_| {
+                core::array::from_fn(|_| td::Td::new())
+            })),
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Push a bus event observed by the USB interrupt handler
+    ///
+    /// Call this only from the USB ISR.
+    pub fn push_event(&self, event: BusEvent) {
+        self.events.push(event);
+    }
+
+    /// Pop the oldest pending bus event, if any
+    ///
+    /// Call this from the main loop to drain events recorded by the
+    /// interrupt handler. Several events may have queued up between
+    /// polls; keep calling this until it returns `None`.
+    pub fn poll_event(&self) -> Option<BusEvent> {
+        self.events.pop()
+    }
+
+    /// Writes the address of the queue heads collection to the
+    /// ENDPTLISTADDR of `usb`
+    ///
+    /// Only use this with the USB instance that owns this state.
+    pub fn assign_endptlistaddr(&self, usb: &ral::usb::Instance) {
+        let ptr = self.qhs.0.as_ptr();
+        ral::write_reg!(ral::usb, usb, ASYNCLISTADDR, ptr as u32);
+    }
+
+    /// "Steal" the queue heads from this state, and return an array of
+    /// references to queue heads
+    ///
+    /// # Safety
+    ///
+    /// This should only be called once per `State`. You must make sure
+    /// that the static, mutable references aren't mutably aliased.
+    /// Consider taking them from this collection, and assigning them
+    /// elsewhere.
+    pub unsafe fn steal_qhs(&'static mut self) -> [Option<&'static mut qh::Qh>; EP] {
+        let mut qhs = core::array::from_fn(|_| None);
+        for (dst, src) in qhs.iter_mut().zip(self.qhs.0.iter_mut()) {
+            *dst = Some(src);
+        }
+        qhs
+    }
+
+    /// "Steal" the transfer descriptors from this state, and return an
+    /// array of transfer descriptor pools, one per queue head
+    ///
+    /// # Safety
+    ///
+    /// This should only be called once per `State`. You must make sure
+    /// that the static, mutable references aren't mutably aliased.
+    /// Consider taking them from this collection, and assigning them
+    /// elsewhere.
+    pub unsafe fn steal_tds(&'static mut self) -> [Option<TdPool>; EP] {
+        let mut tds = core::array::from_fn(|_| None);
+        for (dst, src) in tds.iter_mut().zip(self.tds.0.iter_mut()) {
+            *dst = Some(TdPool::new(src));
+        }
+        tds
+    }
+}
+
+impl<const EP: usize> Default for State<EP> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maximum number of bytes a single dTD can describe
 ///
-/// # Safety
+/// A dTD has five 4 KiB buffer pointers, and the first one may point
+/// anywhere inside its page, so one descriptor can span up to five pages.
+pub const MAX_TRANSFER_BYTES: usize = 5 * 4096;
+
+/// `data` was too large for a single dTD to describe
 ///
-/// This should only be called once. You must make sure that the static, mutable references
-/// aren't mutably aliased. Consider taking them from this collection, and assigning them
-/// elsewhere.
-pub unsafe fn steal_qhs(usb: &ral::usb::Instance) -> [Option<&'static mut qh::Qh>; QH_COUNT] {
-    let mut qhs = [
-        None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-        None,
-    ];
-    for (dst, src) in qhs.iter_mut().zip(state(usb).qhs.0.iter_mut()) {
-        *dst = Some(src);
+/// A dTD has five 4 KiB buffer pointers, so `data.len()` must not exceed
+/// [`MAX_TRANSFER_BYTES`] (20 KiB) minus however many bytes `data` starts
+/// into its first 4 KiB page.
+#[derive(Debug)]
+pub struct TransferTooLarge;
+
+/// Fill `td`'s buffer pointers from `data`, scattering it across the
+/// dTD's five 4 KiB pages
+///
+/// `data` doesn't need to be page-aligned: the first buffer pointer is
+/// `data.as_ptr()`, and each following pointer is the start of the next
+/// 4 KiB page, so the controller keeps DMA'ing across page boundaries in
+/// one descriptor instead of the caller chopping the transfer into
+/// MPS-sized pieces. Because buffer pointer 0 can start mid-page while
+/// pointers 1-4 are full pages, the reachable span shrinks by whatever
+/// offset `data` starts at within its first page; leaves `td` untouched
+/// and returns `Err(TransferTooLarge)` if `data` is longer than that span.
+fn fill_scatter_buffers(td: &mut td::Td, data: &[u8]) -> Result<(), TransferTooLarge> {
+    let base = data.as_ptr() as u32;
+    let offset_in_page = (base & 0xFFF) as usize;
+    let max_len = MAX_TRANSFER_BYTES - offset_in_page;
+    if data.len() > max_len {
+        return Err(TransferTooLarge);
+    }
+
+    let first_page = base & !0xFFF;
+    for (idx, pointer) in td.buffer_pointers_mut().iter_mut().enumerate() {
+        *pointer = if idx == 0 {
+            base
+        } else {
+            first_page.wrapping_add((idx as u32) * 4096)
+        };
     }
-    qhs
+    td.set_total_bytes(data.len() as u32);
+
+    Ok(())
 }
 
-/// "Steal" the transfer descriptors for this USB state, and return an array of transfer
-/// descriptor references.
+/// Maximum number of packets per frame for an isochronous endpoint
 ///
-/// # Safety
+/// The EHCI dQH capabilities `MULT` field allows 1-3 packets per
+/// *microframe*, but microframes are a high-speed (USB 2.0) concept: this
+/// is the `full_speed` driver, and full-speed USB schedules exactly one
+/// transaction per 1 ms frame. So unlike a high-speed driver, this module
+/// only ever queues one packet per frame for an isochronous endpoint, and
+/// `MULT` is always `1`.
+pub const MAX_ISO_MULT: u8 = 1;
+
+/// The requested isochronous `MULT` wasn't `1`, the only packets-per-frame
+/// count full-speed USB (no microframes) can schedule
+#[derive(Debug)]
+pub struct InvalidMult;
+
+/// Configure `qh` as a full-speed isochronous endpoint
 ///
-/// This should only be called once. You must make sure that the static, mutable references
-/// aren't mutably aliased. Consider taking them from this collection, and assigning them
-/// elsewhere.
-pub unsafe fn steal_tds(usb: &ral::usb::Instance) -> [Option<&'static mut td::Td>; QH_COUNT] {
-    let mut tds = [
-        None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-        None,
-    ];
-    for (dst, src) in tds.iter_mut().zip(state(usb).tds.0.iter_mut()) {
-        *dst = Some(src);
+/// Sets the dQH capabilities `MULT` field to `1`, since full-speed has no
+/// microframes to pack more than one packet into. Returns
+/// `Err(InvalidMult)` for any `mult` other than `1`, mainly to catch
+/// callers that copied a high-speed `MULT > 1` configuration over.
+pub fn configure_iso_endpoint(qh: &mut qh::Qh, mult: u8) -> Result<(), InvalidMult> {
+    if mult != MAX_ISO_MULT {
+        return Err(InvalidMult);
+    }
+    qh.set_mult(mult);
+    Ok(())
+}
+
+/// Advance `head` past every slot starting from it that `is_retired`
+/// reports as no longer active, decrementing `len` to match
+///
+/// Pulled out of [`TdPool::retire`] as a plain function over indices so
+/// the reclaim bookkeeping can be unit tested with a fake active/retired
+/// state, without needing real hardware-backed `td::Td`.
+fn advance_head(head: &mut usize, len: &mut usize, capacity: usize, mut is_retired: impl FnMut(usize) -> bool) {
+    while *len > 0 && is_retired(*head) {
+        *head = (*head + 1) % capacity;
+        *len -= 1;
+    }
+}
+
+/// A per-endpoint pool of transfer descriptors
+///
+/// The pool is a linked list of up to `TDS_PER_QH` dTDs. Queuing a new
+/// transfer follows the controller's safe-append procedure, so a transfer
+/// is never lost when hardware is draining the queue concurrently with the
+/// append:
+///
+/// - if the endpoint isn't primed, the new dTD is written into the dQH
+///   overlay, and the endpoint is primed through `ENDPTPRIME`;
+/// - if the endpoint is already primed, the new dTD is linked onto the
+///   tail dTD by clearing the tail's terminate bit and pointing it at the
+///   new dTD, then `ENDPTPRIME` is re-read: if it's still set, hardware
+///   has observed the link and we're done, otherwise the `ATDTW` tripwire
+///   in `USBCMD` is set, `ENDPTSTAT` is read, and if the endpoint went
+///   inactive before the tripwire landed, the dQH is re-primed with the
+///   new dTD.
+pub struct TdPool {
+    tds: &'static mut [td::Td; TDS_PER_QH],
+    head: usize,
+    len: usize,
+}
+
+impl TdPool {
+    fn new(tds: &'static mut [td::Td; TDS_PER_QH]) -> Self {
+        TdPool {
+            tds,
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the number of dTDs currently linked in this pool
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no dTD is currently linked in this pool
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns true if there's no room left to queue another dTD
+    pub fn is_full(&self) -> bool {
+        self.len == self.tds.len()
+    }
+
+    /// Reclaim dTDs that the controller has finished draining
+    ///
+    /// Walks the chain starting at `head`, and for each dTD that's no
+    /// longer marked active (the controller cleared its status `ACTIVE`
+    /// bit once the transfer retired), advances `head` and decrements
+    /// `len`. Stops at the first dTD that's still active, or once every
+    /// queued dTD has been reclaimed.
+    ///
+    /// Call this from the completion/ISR path before queuing more
+    /// transfers. Without it, `queue`/`queue_buffer` permanently return
+    /// `None` once `TDS_PER_QH` transfers have been queued, since nothing
+    /// else frees a slot.
+    pub fn retire(&mut self) {
+        let tds = &self.tds[..];
+        advance_head(&mut self.head, &mut self.len, tds.len(), |i| {
+            !tds[i].is_active()
+        });
+    }
+
+    /// Queue `td` for transfer, linking it onto the endpoint's dTD chain
+    ///
+    /// `qh` is the queue head for this endpoint direction, and `endpt` is
+    /// the `ENDPTPRIME`/`ENDPTSTAT` bit mask for this endpoint direction.
+    /// Returns `None` if the pool has no free slot for another dTD.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure `usb`, `qh`, and `endpt` all refer to the
+    /// same endpoint direction as the dTDs handed out by `steal_tds`.
+    pub unsafe fn queue(
+        &mut self,
+        usb: &ral::usb::Instance,
+        qh: &mut qh::Qh,
+        endpt: u32,
+        td: td::Td,
+    ) -> Option<()> {
+        if self.is_full() {
+            return None;
+        }
+
+        let tail = (self.head + self.len) % self.tds.len();
+        self.tds[tail] = td;
+        self.tds[tail].set_terminate();
+
+        if self.len == 0 {
+            qh.overlay_td(&self.tds[tail]);
+            self.len += 1;
+            ral::write_reg!(ral::usb, usb, ENDPTPRIME, endpt);
+            return Some(());
+        }
+
+        let prev = (self.head + self.len - 1) % self.tds.len();
+        let next_ptr = &self.tds[tail] as *const td::Td;
+        self.tds[prev].link_next(next_ptr);
+        self.len += 1;
+
+        if ral::read_reg!(ral::usb, usb, ENDPTPRIME) & endpt != 0 {
+            return Some(());
+        }
+
+        ral::modify_reg!(ral::usb, usb, USBCMD, ATDTW: 1);
+        let active = ral::read_reg!(ral::usb, usb, ENDPTSTAT) & endpt != 0;
+        if !active {
+            qh.overlay_td(&self.tds[tail]);
+            ral::write_reg!(ral::usb, usb, ENDPTPRIME, endpt);
+        }
+        ral::modify_reg!(ral::usb, usb, USBCMD, ATDTW: 0);
+
+        Some(())
+    }
+
+    /// Queue `data` as a single transfer, scattering it across a dTD's
+    /// five buffer pointers instead of requiring one dTD per MPS-sized
+    /// chunk
+    ///
+    /// Returns `Err(TransferTooLarge)` if `data` is longer than
+    /// [`MAX_TRANSFER_BYTES`] minus its offset into its first 4 KiB page,
+    /// and `Ok(None)` if the pool has no free dTD slot.
+    ///
+    /// # Safety
+    ///
+    /// Same requirements as [`TdPool::queue`].
+    pub unsafe fn queue_buffer(
+        &mut self,
+        usb: &ral::usb::Instance,
+        qh: &mut qh::Qh,
+        endpt: u32,
+        data: &[u8],
+    ) -> Result<Option<()>, TransferTooLarge> {
+        let mut td = td::Td::new();
+        fill_scatter_buffers(&mut td, data)?;
+        Ok(self.queue(usb, qh, endpt, td))
+    }
+
+    /// Queue consecutive frames of an isochronous endpoint
+    ///
+    /// `qh` must already be configured with [`configure_iso_endpoint`].
+    /// Full-speed has no microframes, so unlike a high-speed `MULT > 1`
+    /// endpoint, each buffer here is its own 1 ms frame rather than
+    /// several packets packed into one microframe; otherwise each buffer
+    /// becomes its own dTD, linked onto the chain exactly like
+    /// [`TdPool::queue_buffer`]. Isochronous endpoints don't get a
+    /// different descriptor layout, they just use more of this pool's
+    /// slots over time than a control, bulk, or interrupt endpoint would.
+    /// Stops early if the pool runs out of free slots, returning the
+    /// number of buffers actually queued so the caller can tell a partial
+    /// queue apart from a complete one (compare the result against
+    /// `buffers.len()`; `retire()` may free up slots for the rest).
+    ///
+    /// # Safety
+    ///
+    /// Same requirements as [`TdPool::queue`].
+    pub unsafe fn queue_iso(
+        &mut self,
+        usb: &ral::usb::Instance,
+        qh: &mut qh::Qh,
+        endpt: u32,
+        buffers: &[&[u8]],
+    ) -> Result<usize, TransferTooLarge> {
+        let mut queued = 0;
+        for data in buffers {
+            match self.queue_buffer(usb, qh, endpt, data)? {
+                Some(()) => queued += 1,
+                None => break,
+            }
+        }
+        Ok(queued)
+    }
+}
+
+#[cfg(test)]
+mod advance_head_tests {
+    use super::*;
+
+    #[test]
+    fn stops_at_the_first_slot_still_active() {
+        // Slots 0 and 1 have retired, slot 2 is still active: head should
+        // advance past the two retired slots and stop.
+        let retired = [true, true, false, false];
+        let mut head = 0;
+        let mut len = 4;
+        advance_head(&mut head, &mut len, retired.len(), |i| retired[i]);
+        assert_eq!(head, 2);
+        assert_eq!(len, 2);
+    }
+
+    #[test]
+    fn does_nothing_when_empty() {
+        let mut head = 3;
+        let mut len = 0;
+        advance_head(&mut head, &mut len, 4, |_| true);
+        assert_eq!(head, 3);
+        assert_eq!(len, 0);
+    }
+
+    #[test]
+    fn reclaims_every_slot_and_wraps_the_capacity() {
+        let mut head = 2;
+        let mut len = 4;
+        advance_head(&mut head, &mut len, 4, |_| true);
+        assert_eq!(len, 0);
+        // With everything retired, head wraps back around the ring once
+        // per slot; where it lands no longer matters since len is 0, but
+        // it must stay within bounds.
+        assert!(head < 4);
+    }
+
+    #[test]
+    fn leaves_head_untouched_when_the_oldest_slot_is_still_active() {
+        let retired = [false, true, true, true];
+        let mut head = 0;
+        let mut len = 4;
+        advance_head(&mut head, &mut len, retired.len(), |i| retired[i]);
+        assert_eq!(head, 0);
+        assert_eq!(len, 4);
+    }
+}
+
+#[cfg(test)]
+mod fill_scatter_buffers_tests {
+    use super::*;
+
+    /// Carve a page-aligned, `len`-byte slice out of a backing `Vec`
+    ///
+    /// The host allocator gives no alignment guarantee for an arbitrary
+    /// allocation size, so tests that need a page-aligned buffer over-
+    /// allocate and round the start up to the next 4 KiB boundary
+    /// themselves, the same way `offset_into_first_page_shrinks_...`
+    /// below finds a specific offset into a page.
+    fn page_aligned_slice(backing: &[u8], len: usize) -> &[u8] {
+        let base = backing.as_ptr() as usize;
+        let start = (base + 4095) & !0xFFF;
+        &backing[start - base..start - base + len]
+    }
+
+    #[test]
+    fn page_aligned_buffer_up_to_max_len_is_accepted() {
+        let backing = vec![0u8; MAX_TRANSFER_BYTES + 4096];
+        let data = page_aligned_slice(&backing, MAX_TRANSFER_BYTES);
+        assert_eq!(data.as_ptr() as usize & 0xFFF, 0, "test buffer must be page-aligned");
+        let mut td = td::Td::new();
+        assert!(fill_scatter_buffers(&mut td, data).is_ok());
+    }
+
+    #[test]
+    fn page_aligned_buffer_over_max_len_is_rejected() {
+        let backing = vec![0u8; MAX_TRANSFER_BYTES + 4096];
+        let data = page_aligned_slice(&backing, MAX_TRANSFER_BYTES + 1);
+        assert_eq!(data.as_ptr() as usize & 0xFFF, 0, "test buffer must be page-aligned");
+        let mut td = td::Td::new();
+        assert!(fill_scatter_buffers(&mut td, data).is_err());
+    }
+
+    #[test]
+    fn offset_into_first_page_shrinks_the_allowed_length() {
+        // A buffer that doesn't start at a page boundary loses however
+        // many bytes it's offset into its first page: pointer 0 covers
+        // only the rest of that page, not a full page like pointers 1-4.
+        let backing = vec![0u8; MAX_TRANSFER_BYTES + 4096];
+        let base = backing.as_ptr() as usize;
+        let offset_in_page = 16;
+        let start = ((base + 4095) & !0xFFF) + offset_in_page;
+        let data = &backing[start - base..start - base + (MAX_TRANSFER_BYTES - offset_in_page + 1)];
+
+        let mut td = td::Td::new();
+        assert!(
+            fill_scatter_buffers(&mut td, data).is_err(),
+            "length that only fits when page-aligned must be rejected once offset"
+        );
+    }
+}
+
+#[cfg(test)]
+mod event_queue_tests {
+    use super::*;
+
+    #[test]
+    fn fill_then_drain_returns_events_in_order() {
+        let queue = EventQueue::new();
+        queue.push(BusEvent::Reset);
+        queue.push(BusEvent::Suspend);
+        queue.push(BusEvent::Resume);
+
+        assert_eq!(queue.pop(), Some(BusEvent::Reset));
+        assert_eq!(queue.pop(), Some(BusEvent::Suspend));
+        assert_eq!(queue.pop(), Some(BusEvent::Resume));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn push_past_capacity_drops_the_newest_event_and_keeps_order() {
+        let queue = EventQueue::new();
+        for _ in 0..EVENT_QUEUE_CAPACITY {
+            queue.push(BusEvent::PortChange);
+        }
+        // The queue is now full; this one has nowhere to go.
+        queue.push(BusEvent::Error);
+
+        for _ in 0..EVENT_QUEUE_CAPACITY - 1 {
+            assert_eq!(queue.pop(), Some(BusEvent::PortChange));
+        }
+        assert_eq!(queue.pop(), None, "dropped event must not appear later");
+    }
+
+    #[test]
+    fn wraps_around_the_backing_array() {
+        let queue = EventQueue::new();
+        for _ in 0..EVENT_QUEUE_CAPACITY * 3 {
+            queue.push(BusEvent::Reset);
+            assert_eq!(queue.pop(), Some(BusEvent::Reset));
+        }
+        assert_eq!(queue.pop(), None);
     }
-    tds
 }